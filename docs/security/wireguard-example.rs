@@ -1,8 +1,13 @@
 use boringtun::noise::{Tunn, TunnResult};
 use boringtun::x25519::{PublicKey, StaticSecret};
 use rand_core::OsRng;
-use std::collections::VecDeque;
-use std::net::{IpAddr, Ipv4Addr};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
 
 fn main() {
     // ---- Generate static keys for two peers: A (initiator) and B (responder)
@@ -13,25 +18,49 @@ fn main() {
 
     // ---- Build Tunn state machines (no PSK, keepalive 25s, no rate limiter)
     // index is an arbitrary u32 you choose per peer.
-    let mut a = Tunn::new(a_sk, b_pk, None, Some(25), 0, None).expect("A Tunn::new");
-    let mut b = Tunn::new(b_sk, a_pk, None, Some(25), 1, None).expect("B Tunn::new");
-
-    // ---- In-memory "network" channels and "tun" queues
-    // a2b_net/b2a_net simulate UDP between the peers.
-    // a_tun/b_tun simulate the OS TUN interfaces (IP packets in/out).
-    let mut a2b_net: VecDeque<Vec<u8>> = VecDeque::new();
-    let mut b2a_net: VecDeque<Vec<u8>> = VecDeque::new();
+    let a_tunn = Tunn::new(a_sk, b_pk, None, Some(25), 0, None).expect("A Tunn::new");
+    let b_tunn = Tunn::new(b_sk, a_pk, None, Some(25), 1, None).expect("B Tunn::new");
+
+    // ---- In-memory transports stand in for the real `UdpTransport`: they
+    // implement the same `Transport` trait, so this demo (and offline
+    // tests) can run without binding real sockets.
+    let addr_a: SocketAddr = ([127, 0, 0, 1], 51820).into();
+    let addr_b: SocketAddr = ([127, 0, 0, 1], 51821).into();
+    let (transport_a, transport_b) = InMemoryTransport::pair(addr_a, addr_b);
+
+    // ---- Build a Device per endpoint, each owning its peer table and
+    // AllowedIPs routing table instead of a single hard-coded Tunn. Peer
+    // sessions live behind per-peer `Mutex`es so the same `Device` can later
+    // be shared across a `ParallelQueue` of crypto workers. A already knows
+    // B's endpoint; B will learn A's by roaming once an authenticated data
+    // packet from A arrives.
+    let dev_a = Device::new();
+    dev_a.add_peer(
+        b_pk,
+        a_tunn,
+        Some(addr_b),
+        vec![IpNet::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 32).unwrap()],
+    );
+    let dev_b = Device::new();
+    dev_b.add_peer(
+        a_pk,
+        b_tunn,
+        None,
+        vec![IpNet::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 32).unwrap()],
+    );
+
+    // ---- a_tun/b_tun simulate the OS TUN interfaces (IP packets in/out).
     let mut a_tun: VecDeque<Vec<u8>> = VecDeque::new();
     let mut b_tun: VecDeque<Vec<u8>> = VecDeque::new();
 
     // ---- Kick off a handshake from A
     let mut out = vec![0u8; 2048];
-    if let TunnResult::WriteToNetwork(pkt) = a.format_handshake_initiation(&mut out, false) {
-        a2b_net.push_back(pkt.to_vec());
+    if let Some((pkt, dest)) = dev_a.initiate_handshake(&b_pk, &mut out) {
+        transport_a.send_to(pkt, dest).expect("send handshake init");
     }
 
-    // ---- Pump the in-memory network until the handshake completes
-    pump(&mut a, &mut b, &mut a2b_net, &mut b2a_net, &mut a_tun, &mut b_tun);
+    // ---- Pump the transports until the handshake completes
+    pump(&dev_a, &transport_a, &dev_b, &transport_b, &mut a_tun, &mut b_tun);
 
     // ---- Build a small dummy IPv4/UDP packet to send over the tunnel
     let inner = build_ipv4_udp(
@@ -42,15 +71,17 @@ fn main() {
         b"hello over boringtun".as_ref(),
     );
 
-    // ---- Encapsulate on A (like writing to /dev/net/tun), producing a WG datagram
+    // ---- Encapsulate on A: the destination address in the inner packet
+    // selects the peer via dev_a's AllowedIPs routing table, and the
+    // packet is sent to that peer's current (roaming) endpoint.
     let mut enc_buf = vec![0u8; inner.len() + 256]; // >= inner + 32 bytes headroom
-    match a.encapsulate(&inner, &mut enc_buf) {
-        TunnResult::WriteToNetwork(wg) => a2b_net.push_back(wg.to_vec()),
-        other => panic!("unexpected encapsulate result: {:?}", other),
+    match dev_a.encapsulate(&inner, &mut enc_buf) {
+        Some((wg, dest)) => transport_a.send_to(wg, dest).expect("send data packet"),
+        None => panic!("unexpected encapsulate result: no route or handshake not ready"),
     }
 
     // ---- Pump again so B receives and decapsulates to its TUN queue
-    pump(&mut a, &mut b, &mut a2b_net, &mut b2a_net, &mut a_tun, &mut b_tun);
+    pump(&dev_a, &transport_a, &dev_b, &transport_b, &mut a_tun, &mut b_tun);
 
     // ---- Verify the inner packet arrived at B
     let received = b_tun.pop_front().expect("B should receive inner packet");
@@ -58,69 +89,733 @@ fn main() {
     println!("OK ✅  B received {} bytes over the tunnel", received.len());
 }
 
-/// Move datagrams across the "wire" until there’s nothing left to do.
-/// This processes handshake retries, keepalives, and data.
-/// It follows the docs for `decapsulate`: if we get `WriteToNetwork`,
-/// call again with an empty datagram until `Done`.  [oai_citation:1‡Docs.rs](https://docs.rs/boringtun/latest/boringtun/noise/struct.Tunn.html)
+/// Move datagrams across the transports until there's nothing left to do.
+/// This carries handshake messages, keepalives, and data; timers are never
+/// ticked (`update_timers` is not called), so no handshake retries are
+/// sent. It follows the
+/// docs for `decapsulate`: if we get `WriteToNetwork`, call again with an
+/// empty datagram until `Done`.  [oai_citation:1‡Docs.rs](https://docs.rs/boringtun/latest/boringtun/noise/struct.Tunn.html)
 fn pump(
-    a: &mut Tunn,
-    b: &mut Tunn,
-    a2b_net: &mut VecDeque<Vec<u8>>,
-    b2a_net: &mut VecDeque<Vec<u8>>,
+    a: &Device,
+    a_transport: &dyn Transport,
+    b: &Device,
+    b_transport: &dyn Transport,
     a_tun: &mut VecDeque<Vec<u8>>,
     b_tun: &mut VecDeque<Vec<u8>>,
 ) {
     loop {
-        let p1 = process_incoming(a, b2a_net, a2b_net, a_tun, "A");
-        let p2 = process_incoming(b, a2b_net, b2a_net, b_tun, "B");
+        let p1 = process_incoming(a, a_transport, a_tun, "A");
+        let p2 = process_incoming(b, b_transport, b_tun, "B");
         if !(p1 || p2) {
             break;
         }
     }
 }
 
+/// Drain every datagram currently waiting on `transport`, feeding each one
+/// through `Device::decapsulate` and routing the resulting events: forward
+/// `ToNetwork` packets back out over the same transport, and queue tunnel
+/// deliveries onto `out_tun` as if they'd been written to a TUN device.
 fn process_incoming(
-    me: &mut Tunn,
-    incoming_net: &mut VecDeque<Vec<u8>>,
-    outgoing_net: &mut VecDeque<Vec<u8>>,
+    me: &Device,
+    transport: &dyn Transport,
     out_tun: &mut VecDeque<Vec<u8>>,
     who: &str,
 ) -> bool {
     let mut did_any = false;
+    let mut buf = vec![0u8; 65536];
 
-    while let Some(datagram) = incoming_net.pop_front() {
+    loop {
+        let (n, from) = match transport.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => {
+                eprintln!("{who} recv error: {e}");
+                break;
+            }
+        };
         did_any = true;
-        let mut scratch = vec![0u8; 65536];
-
-        // parse the received WG/UDP datagram
-        let mut res = me.decapsulate(None::<IpAddr>, &datagram, &mut scratch);
 
-        loop {
-            match res {
-                TunnResult::WriteToNetwork(packet) => {
-                    // handshake response, cookie, keepalive, or data that must be forwarded
-                    outgoing_net.push_back(packet.to_vec());
-                    // IMPORTANT: call again with empty datagram until Done (per docs)
-                    res = me.decapsulate(None::<IpAddr>, &[], &mut scratch);
+        // `Device::decapsulate` internally follows the documented protocol
+        // for `Tunn::decapsulate` (call again with an empty datagram until
+        // `Done`) and returns the resulting events already reassembled.
+        for event in me.decapsulate(&buf[..n], from) {
+            match event {
+                DeviceEvent::ToNetwork(packet, dest) => {
+                    let _ = transport.send_to(&packet, dest);
                 }
-                TunnResult::WriteToTunnelV4(inner, _src) => {
-                    out_tun.push_back(inner.to_vec());
-                    break;
+                DeviceEvent::ToTunnelV4(inner, _src) => out_tun.push_back(inner),
+                DeviceEvent::ToTunnelV6(inner, _src) => out_tun.push_back(inner),
+            }
+        }
+    }
+
+    did_any
+}
+
+/// A CIDR range: an address together with its prefix length. IPv4 prefixes
+/// run 0..=32, IPv6 prefixes run 0..=128.
+#[derive(Clone, Copy, Debug)]
+struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    /// `None` if `prefix_len` doesn't fit the address family (IPv4 allows
+    /// 0..=32, IPv6 allows 0..=128) — `TrieNode::insert` trusts the prefix
+    /// length to index into the address bytes, so an oversized one must be
+    /// rejected here rather than there.
+    fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max {
+            return None;
+        }
+        Some(IpNet { addr, prefix_len })
+    }
+}
+
+/// One node of the binary trie backing `AllowedIps`. Each level of the trie
+/// consumes one bit of the address; a node carries a peer once some inserted
+/// prefix terminates there.
+#[derive(Default)]
+struct TrieNode {
+    peer: Option<PublicKey>,
+    children: [Option<Box<TrieNode>>; 2],
+}
+
+impl TrieNode {
+    fn insert(&mut self, addr_bytes: &[u8], prefix_len: u8, pubkey: PublicKey) {
+        let mut node = self;
+        for bit_index in 0..prefix_len as usize {
+            let bit = bit_at(addr_bytes, bit_index);
+            node = node.children[bit as usize].get_or_insert_with(|| Box::new(TrieNode::default()));
+        }
+        node.peer = Some(pubkey);
+    }
+
+    /// Walk the trie along `addr_bytes`, remembering the deepest node that
+    /// carries a peer assignment. That is the longest-prefix match.
+    fn longest_match(&self, addr_bytes: &[u8]) -> Option<PublicKey> {
+        let mut node = self;
+        let mut best = node.peer;
+        for bit_index in 0..addr_bytes.len() * 8 {
+            let bit = bit_at(addr_bytes, bit_index);
+            match &node.children[bit as usize] {
+                Some(child) => {
+                    node = child;
+                    if node.peer.is_some() {
+                        best = node.peer;
+                    }
                 }
-                TunnResult::WriteToTunnelV6(inner, _src) => {
-                    out_tun.push_back(inner.to_vec());
-                    break;
+                None => break,
+            }
+        }
+        best
+    }
+
+    fn remove_peer(&mut self, pubkey: &PublicKey) {
+        if self.peer.as_ref() == Some(pubkey) {
+            self.peer = None;
+        }
+        for child in self.children.iter_mut().flatten() {
+            child.remove_peer(pubkey);
+        }
+    }
+}
+
+fn bit_at(bytes: &[u8], bit_index: usize) -> u8 {
+    (bytes[bit_index / 8] >> (7 - bit_index % 8)) & 1
+}
+
+/// Longest-prefix-match routing table mapping AllowedIPs CIDR ranges to
+/// peers, keyed by address family.
+#[derive(Default)]
+struct AllowedIps {
+    v4: TrieNode,
+    v6: TrieNode,
+}
+
+impl AllowedIps {
+    fn new() -> Self {
+        AllowedIps::default()
+    }
+
+    fn insert(&mut self, net: IpNet, pubkey: PublicKey) {
+        match net.addr {
+            IpAddr::V4(addr) => self.v4.insert(&addr.octets(), net.prefix_len, pubkey),
+            IpAddr::V6(addr) => self.v6.insert(&addr.octets(), net.prefix_len, pubkey),
+        }
+    }
+
+    fn remove_peer(&mut self, pubkey: &PublicKey) {
+        self.v4.remove_peer(pubkey);
+        self.v6.remove_peer(pubkey);
+    }
+
+    fn find(&self, dest: IpAddr) -> Option<PublicKey> {
+        match dest {
+            IpAddr::V4(addr) => self.v4.longest_match(&addr.octets()),
+            IpAddr::V6(addr) => self.v6.longest_match(&addr.octets()),
+        }
+    }
+}
+
+/// The mutable per-session state of a peer: its `Tunn` noise state machine
+/// and its anti-replay window. Bundled behind a single `Mutex` so that an
+/// encapsulate/decapsulate call locks both together for its duration.
+struct PeerSession {
+    tunn: Tunn,
+    replay_filter: ReplayFilter,
+}
+
+/// A single configured peer: its session state, last-known roaming
+/// endpoint, and the AllowedIPs ranges it is authorized to source and sink
+/// traffic for. `allowed_ips` is set once at `add_peer` time and never
+/// mutated afterwards, so it needs no lock of its own.
+struct Peer {
+    session: Mutex<PeerSession>,
+    endpoint: Mutex<Option<SocketAddr>>,
+    allowed_ips: Vec<IpNet>,
+}
+
+// RFC 6479 redundant-block bitmap, word size matched to the target pointer
+// width like boringtun's own replay filter.
+#[cfg(target_pointer_width = "64")]
+type Word = u64;
+#[cfg(target_pointer_width = "32")]
+type Word = u32;
+
+const SIZE_OF_WORD: usize = std::mem::size_of::<Word>() * 8;
+const BITMAP_BITLEN: usize = 2048;
+const BITMAP_LEN: usize = BITMAP_BITLEN / SIZE_OF_WORD;
+const REDUNDANT_BIT_SHIFTS: usize = SIZE_OF_WORD.trailing_zeros() as usize;
+const BITMAP_INDEX_MASK: u64 = (BITMAP_LEN - 1) as u64;
+const BITMAP_LOC_MASK: u64 = (SIZE_OF_WORD - 1) as u64;
+const WINDOW_SIZE: u64 = (BITMAP_BITLEN - SIZE_OF_WORD) as u64;
+
+/// Per-session sliding-window anti-replay filter (RFC 6479). Tracked
+/// per-peer, alongside its `Tunn`, so each session direction gets its own
+/// window instead of relying solely on boringtun's internal bookkeeping.
+struct ReplayFilter {
+    last: u64,
+    bitmap: [Word; BITMAP_LEN],
+}
+
+impl ReplayFilter {
+    fn new() -> Self {
+        ReplayFilter {
+            last: 0,
+            bitmap: [0; BITMAP_LEN],
+        }
+    }
+
+    /// Validate `seq` against the window and, only once accepted, record it.
+    /// Returns `false` if `seq` is a replay or too far behind the last seen
+    /// counter.
+    fn validate_and_update(&mut self, seq: u64) -> bool {
+        if seq > self.last {
+            let old_block = self.last >> REDUNDANT_BIT_SHIFTS;
+            let new_block = seq >> REDUNDANT_BIT_SHIFTS;
+            let clear = (new_block - old_block).min(BITMAP_LEN as u64);
+            for i in 1..=clear {
+                let idx = (old_block + i) & BITMAP_INDEX_MASK;
+                self.bitmap[idx as usize] = 0;
+            }
+            self.last = seq;
+        } else if seq.checked_add(WINDOW_SIZE).is_none_or(|bound| bound < self.last) {
+            return false;
+        }
+
+        let index = (seq >> REDUNDANT_BIT_SHIFTS) & BITMAP_INDEX_MASK;
+        let bit: Word = 1 << (seq & BITMAP_LOC_MASK);
+        if self.bitmap[index as usize] & bit != 0 {
+            return false;
+        }
+        self.bitmap[index as usize] |= bit;
+        true
+    }
+}
+
+/// Parse the little-endian packet counter out of a WireGuard transport data
+/// message (type 0x04: 1-byte type, 3 reserved bytes, 4-byte receiver
+/// index, 8-byte counter, then the encrypted payload).
+fn data_packet_counter(datagram: &[u8]) -> Option<u64> {
+    if datagram.len() < 16 || datagram[0] != 4 {
+        return None;
+    }
+    Some(u64::from_le_bytes(datagram[8..16].try_into().unwrap()))
+}
+
+/// Owns every configured peer plus the AllowedIPs routing table used to
+/// pick a peer for an outbound packet and to validate the source of an
+/// inbound one. Replaces the old hard-coded two-`Tunn` wiring.
+///
+/// The peer map and routing table are behind `RwLock`s rather than requiring
+/// `&mut Device`, so the map can be read concurrently by many crypto
+/// workers (see `spawn`/`ParallelQueue`) while only the individual peer
+/// session that's actually doing work is locked.
+#[derive(Default)]
+struct Device {
+    peers: RwLock<HashMap<PublicKey, Arc<Peer>>>,
+    allowed_ips: RwLock<AllowedIps>,
+}
+
+impl Device {
+    fn new() -> Self {
+        Device::default()
+    }
+
+    fn add_peer(
+        &self,
+        pubkey: PublicKey,
+        tunn: Tunn,
+        endpoint: Option<SocketAddr>,
+        allowed_ips: Vec<IpNet>,
+    ) {
+        let mut table = self.allowed_ips.write().unwrap();
+        for net in &allowed_ips {
+            table.insert(*net, pubkey);
+        }
+        drop(table);
+        self.peers.write().unwrap().insert(
+            pubkey,
+            Arc::new(Peer {
+                session: Mutex::new(PeerSession {
+                    tunn,
+                    replay_filter: ReplayFilter::new(),
+                }),
+                endpoint: Mutex::new(endpoint),
+                allowed_ips,
+            }),
+        );
+    }
+
+    fn remove_peer(&self, pubkey: &PublicKey) {
+        self.allowed_ips.write().unwrap().remove_peer(pubkey);
+        self.peers.write().unwrap().remove(pubkey);
+    }
+
+    fn peer(&self, pubkey: &PublicKey) -> Option<Arc<Peer>> {
+        self.peers.read().unwrap().get(pubkey).cloned()
+    }
+
+    /// Kick off a handshake with an already-configured peer, returning the
+    /// packet to send together with the peer's current endpoint. `None` if
+    /// the peer is unknown or has no known endpoint yet.
+    fn initiate_handshake<'a>(
+        &self,
+        pubkey: &PublicKey,
+        dst_buf: &'a mut [u8],
+    ) -> Option<(&'a [u8], SocketAddr)> {
+        let peer = self.peer(pubkey)?;
+        let dest = (*peer.endpoint.lock().unwrap())?;
+        let mut session = peer.session.lock().unwrap();
+        match session.tunn.format_handshake_initiation(dst_buf, false) {
+            TunnResult::WriteToNetwork(packet) => Some((packet, dest)),
+            _ => None,
+        }
+    }
+
+    /// Encapsulate an outbound inner packet (IPv4 or IPv6), routing it to
+    /// whichever peer's AllowedIPs cover the packet's destination address,
+    /// and returning the packet to send together with that peer's current
+    /// endpoint. The peer map is only read-locked long enough to clone the
+    /// target `Arc<Peer>`; the session `Mutex` is then held just for the
+    /// encapsulate call itself. `None` if there's no route or the peer has
+    /// no endpoint yet.
+    fn encapsulate<'a>(&self, inner: &[u8], dst_buf: &'a mut [u8]) -> Option<(&'a [u8], SocketAddr)> {
+        let dest = inner_dest_addr(inner)?;
+        let pubkey = self.allowed_ips.read().unwrap().find(dest)?;
+        let peer = self.peer(&pubkey)?;
+        let endpoint = (*peer.endpoint.lock().unwrap())?;
+        let mut session = peer.session.lock().unwrap();
+        match session.tunn.encapsulate(inner, dst_buf) {
+            TunnResult::WriteToNetwork(packet) => Some((packet, endpoint)),
+            _ => None,
+        }
+    }
+
+    /// Decapsulate an inbound WireGuard datagram received from `from`. Since
+    /// a `Tunn` session is peer-specific, each configured peer is tried in
+    /// turn, locking only its own session for the duration of the attempt,
+    /// until one decrypts it. The documented `Tunn::decapsulate` protocol
+    /// (call again with an empty datagram until `Done`) is then followed for
+    /// that one session, converting each step to an owned `DeviceEvent` so
+    /// the result can outlive this call. On the terminal tunnel delivery,
+    /// the packet's anti-replay counter and AllowedIPs-sourced address are
+    /// validated before the event is emitted, so a replayed or
+    /// spoofed-source packet is silently dropped instead; a packet that
+    /// passes both checks also updates the peer's roaming endpoint to
+    /// `from`, following wireguard-rs's approach of trusting the most
+    /// recent authenticated source address over any statically configured
+    /// one.
+    fn decapsulate(&self, datagram: &[u8], from: SocketAddr) -> Vec<DeviceEvent> {
+        let counter = data_packet_counter(datagram);
+        let candidates: Vec<Arc<Peer>> = self.peers.read().unwrap().values().cloned().collect();
+
+        for peer in &candidates {
+            let mut session = peer.session.lock().unwrap();
+            let mut scratch = vec![0u8; 65536];
+            let mut res = session.tunn.decapsulate(None::<IpAddr>, datagram, &mut scratch);
+            if matches!(res, TunnResult::Err(_)) {
+                continue;
+            }
+
+            let mut events = Vec::new();
+            loop {
+                match res {
+                    TunnResult::WriteToNetwork(packet) => {
+                        events.push(DeviceEvent::ToNetwork(packet.to_vec(), from));
+                        res = session.tunn.decapsulate(None::<IpAddr>, &[], &mut scratch);
+                    }
+                    TunnResult::WriteToTunnelV4(inner, src) => {
+                        let accepted = counter
+                            .is_none_or(|seq| session.replay_filter.validate_and_update(seq))
+                            && ipv4_src_allowed(inner, &peer.allowed_ips);
+                        if accepted {
+                            *peer.endpoint.lock().unwrap() = Some(from);
+                            events.push(DeviceEvent::ToTunnelV4(inner.to_vec(), src));
+                        }
+                        break;
+                    }
+                    TunnResult::WriteToTunnelV6(inner, src) => {
+                        let accepted = counter
+                            .is_none_or(|seq| session.replay_filter.validate_and_update(seq))
+                            && ipv6_src_allowed(inner, &peer.allowed_ips);
+                        if accepted {
+                            *peer.endpoint.lock().unwrap() = Some(from);
+                            events.push(DeviceEvent::ToTunnelV6(inner.to_vec(), src));
+                        }
+                        break;
+                    }
+                    TunnResult::Done | TunnResult::Err(_) => break,
                 }
-                TunnResult::Done => break,
-                TunnResult::Err(e) => {
-                    eprintln!("{who} decap error: {e:?}");
-                    break;
+            }
+            return events;
+        }
+        Vec::new()
+    }
+}
+
+/// An inbound effect produced by `Device::decapsulate`, with its payload
+/// already copied out of the scratch buffer. `ToNetwork` carries the
+/// destination the reply should be sent to (the datagram's own source,
+/// since these are handshake responses/cookies/keepalives addressed back
+/// to whoever we just heard from).
+enum DeviceEvent {
+    ToNetwork(Vec<u8>, SocketAddr),
+    ToTunnelV4(Vec<u8>, Ipv4Addr),
+    ToTunnelV6(Vec<u8>, Ipv6Addr),
+}
+
+/// Every WireGuard message type carries a session index at bytes 4..8 (the
+/// sender's index on a handshake initiation, the receiver's index on
+/// everything after). Hashing that index picks the same worker for every
+/// datagram belonging to one session, so a session's packets can never be
+/// decapsulated out of order across two different worker threads. Falls
+/// back to worker 0 for anything too short to carry an index.
+fn worker_index_for(datagram: &[u8], workers: usize) -> usize {
+    match datagram.get(4..8) {
+        Some(index_bytes) => u32::from_le_bytes(index_bytes.try_into().unwrap()) as usize % workers,
+        None => 0,
+    }
+}
+
+/// One inbound datagram dispatched to a crypto worker, tagged with its
+/// submission order so results can be reassembled in sequence afterwards,
+/// and with the source address it arrived from.
+struct InboundJob {
+    seq: u64,
+    datagram: Vec<u8>,
+    from: SocketAddr,
+}
+
+/// The outcome of running an `InboundJob` through `Device::decapsulate`,
+/// still tagged with its originating sequence number. Carries every
+/// `DeviceEvent` the decapsulation produced, not just a tunnel payload, so
+/// `ToNetwork` replies (handshake responses, cookies, keepalives) still
+/// reach the caller when dispatched through the pool.
+struct InboundResult {
+    seq: u64,
+    events: Vec<DeviceEvent>,
+}
+
+/// A worker pool: `workers` threads each own a bounded channel. Datagrams
+/// are routed to a worker by hashing the session index carried in every
+/// WireGuard message (see `worker_index_for`), not round-robined, so every
+/// datagram for a given peer session always lands on the same worker and
+/// can never race another worker over that peer's `ReplayFilter`/`Mutex`.
+/// Results are additionally reassembled by submission sequence before
+/// being handed to the caller.
+struct ParallelQueue {
+    senders: Vec<SyncSender<InboundJob>>,
+    handles: Vec<JoinHandle<()>>,
+    results: Receiver<InboundResult>,
+    submitted: u64,
+    expected: u64,
+    pending: Mutex<BTreeMap<u64, Vec<DeviceEvent>>>,
+}
+
+impl ParallelQueue {
+    fn new(device: Arc<Device>, workers: usize) -> Self {
+        assert!(workers > 0, "ParallelQueue needs at least one worker");
+        let (result_tx, result_rx) = sync_channel(1024);
+        let mut senders = Vec::with_capacity(workers);
+        let mut handles = Vec::with_capacity(workers);
+        for _ in 0..workers {
+            let (tx, rx) = sync_channel::<InboundJob>(256);
+            let device = Arc::clone(&device);
+            let result_tx = result_tx.clone();
+            handles.push(thread::spawn(move || {
+                while let Ok(job) = rx.recv() {
+                    let events = device.decapsulate(&job.datagram, job.from);
+                    if result_tx.send(InboundResult { seq: job.seq, events }).is_err() {
+                        break;
+                    }
                 }
+            }));
+            senders.push(tx);
+        }
+        ParallelQueue {
+            senders,
+            handles,
+            results: result_rx,
+            submitted: 0,
+            expected: 0,
+            pending: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Route an inbound datagram to the worker owning its session and
+    /// return the sequence number it was submitted under.
+    fn submit(&mut self, datagram: Vec<u8>, from: SocketAddr) -> u64 {
+        let seq = self.submitted;
+        self.submitted += 1;
+        let i = worker_index_for(&datagram, self.senders.len());
+        let _ = self.senders[i].send(InboundJob { seq, datagram, from });
+        seq
+    }
+
+    /// Drain completed jobs and return those that are now next-in-order,
+    /// in order. Jobs that finish ahead of an earlier one still in flight
+    /// are buffered until the gap closes.
+    fn poll_ordered(&mut self) -> Vec<Vec<DeviceEvent>> {
+        let mut pending = self.pending.lock().unwrap();
+        while let Ok(result) = self.results.try_recv() {
+            pending.insert(result.seq, result.events);
+        }
+        drain_ready(&mut pending, &mut self.expected)
+    }
+}
+
+/// Pull every entry out of `pending` starting at `*expected` for as long as
+/// the run is unbroken, advancing `*expected` past each one. Split out of
+/// `poll_ordered` so the reassembly logic can be tested directly against a
+/// plain `BTreeMap`, without real worker threads or their scheduling
+/// nondeterminism.
+fn drain_ready(
+    pending: &mut BTreeMap<u64, Vec<DeviceEvent>>,
+    expected: &mut u64,
+) -> Vec<Vec<DeviceEvent>> {
+    let mut ready = Vec::new();
+    while let Some(events) = pending.remove(expected) {
+        ready.push(events);
+        *expected += 1;
+    }
+    ready
+}
+
+impl Drop for ParallelQueue {
+    fn drop(&mut self) {
+        self.senders.clear(); // closes each worker's channel so recv() returns Err
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Device {
+    /// Spin up a `ParallelQueue` of `workers` crypto threads sharing this
+    /// device for decapsulation.
+    fn spawn(self: &Arc<Self>, workers: usize) -> ParallelQueue {
+        ParallelQueue::new(Arc::clone(self), workers)
+    }
+}
+
+/// The datagram transport a `Device` is run over. Non-blocking: a `recv_from`
+/// with nothing waiting returns `io::ErrorKind::WouldBlock` rather than
+/// parking the caller, so a single thread can poll several transports (or
+/// drain one transport fully) without dedicating a thread per peer.
+/// `UdpTransport` is the real implementation; `InMemoryTransport` is a
+/// channel-backed stand-in so the demo and tests can run without binding
+/// sockets.
+trait Transport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<()>;
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+}
+
+/// A single dual-stack UDP socket: `IPV6_V6ONLY` is disabled so it accepts
+/// both IPv4-mapped and native IPv6 peers on the same port.
+struct UdpTransport {
+    socket: std::net::UdpSocket,
+}
+
+impl UdpTransport {
+    fn bind(port: u16) -> io::Result<Self> {
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_only_v6(false)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        Ok(UdpTransport {
+            socket: socket.into(),
+        })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<()> {
+        self.socket.send_to(buf, addr)?;
+        Ok(())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket.recv_from(buf)
+    }
+}
+
+/// A channel-backed stand-in for `UdpTransport` that hands datagrams
+/// straight to its paired peer, so the demo (and offline tests) can run
+/// without binding real sockets.
+struct InMemoryTransport {
+    local_addr: SocketAddr,
+    inbox: Receiver<(Vec<u8>, SocketAddr)>,
+    peer: SyncSender<(Vec<u8>, SocketAddr)>,
+}
+
+impl InMemoryTransport {
+    /// Build a pair of transports wired crosswise: datagrams sent on one
+    /// arrive, tagged with `addr_a`/`addr_b`, on the other's `recv_from`.
+    fn pair(addr_a: SocketAddr, addr_b: SocketAddr) -> (Self, Self) {
+        let (tx_a_to_b, rx_a_to_b) = sync_channel(64);
+        let (tx_b_to_a, rx_b_to_a) = sync_channel(64);
+        let a = InMemoryTransport {
+            local_addr: addr_a,
+            inbox: rx_b_to_a,
+            peer: tx_a_to_b,
+        };
+        let b = InMemoryTransport {
+            local_addr: addr_b,
+            inbox: rx_a_to_b,
+            peer: tx_b_to_a,
+        };
+        (a, b)
+    }
+}
+
+impl Transport for InMemoryTransport {
+    fn send_to(&self, buf: &[u8], _addr: SocketAddr) -> io::Result<()> {
+        self.peer
+            .send((buf.to_vec(), self.local_addr))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self.inbox.try_recv() {
+            Ok((datagram, from)) => {
+                let n = datagram.len().min(buf.len());
+                buf[..n].copy_from_slice(&datagram[..n]);
+                Ok((n, from))
             }
+            Err(TryRecvError::Empty) => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+            Err(TryRecvError::Disconnected) => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
         }
     }
+}
+
+/// Read the destination address out of an IPv4 header (offset 16..20, as
+/// written by `build_ipv4_udp`).
+fn ipv4_dest_addr(inner: &[u8]) -> Option<Ipv4Addr> {
+    if inner.len() < 20 || inner[0] >> 4 != 4 {
+        return None;
+    }
+    Some(Ipv4Addr::new(inner[16], inner[17], inner[18], inner[19]))
+}
 
-    did_any
+/// Read the source address out of an IPv4 header (offset 12..16).
+fn ipv4_src_addr(inner: &[u8]) -> Option<Ipv4Addr> {
+    if inner.len() < 20 || inner[0] >> 4 != 4 {
+        return None;
+    }
+    Some(Ipv4Addr::new(inner[12], inner[13], inner[14], inner[15]))
+}
+
+fn ipv4_src_allowed(inner: &[u8], allowed_ips: &[IpNet]) -> bool {
+    match ipv4_src_addr(inner) {
+        Some(src) => allowed_ips
+            .iter()
+            .any(|net| net_contains(*net, IpAddr::V4(src))),
+        None => false,
+    }
+}
+
+/// Read the destination address out of an IPv6 header (offset 24..40, as
+/// written by `build_ipv6_udp`).
+fn ipv6_dest_addr(inner: &[u8]) -> Option<Ipv6Addr> {
+    if inner.len() < 40 || inner[0] >> 4 != 6 {
+        return None;
+    }
+    Some(Ipv6Addr::from(<[u8; 16]>::try_from(&inner[24..40]).unwrap()))
+}
+
+/// Read the source address out of an IPv6 header (offset 8..24).
+fn ipv6_src_addr(inner: &[u8]) -> Option<Ipv6Addr> {
+    if inner.len() < 40 || inner[0] >> 4 != 6 {
+        return None;
+    }
+    Some(Ipv6Addr::from(<[u8; 16]>::try_from(&inner[8..24]).unwrap()))
+}
+
+fn ipv6_src_allowed(inner: &[u8], allowed_ips: &[IpNet]) -> bool {
+    match ipv6_src_addr(inner) {
+        Some(src) => allowed_ips
+            .iter()
+            .any(|net| net_contains(*net, IpAddr::V6(src))),
+        None => false,
+    }
+}
+
+/// Read an inner packet's destination address regardless of IP version, for
+/// `Device::encapsulate`'s AllowedIPs lookup.
+fn inner_dest_addr(inner: &[u8]) -> Option<IpAddr> {
+    match inner.first()? >> 4 {
+        4 => ipv4_dest_addr(inner).map(IpAddr::V4),
+        6 => ipv6_dest_addr(inner).map(IpAddr::V6),
+        _ => None,
+    }
+}
+
+fn net_contains(net: IpNet, addr: IpAddr) -> bool {
+    match (net.addr, addr) {
+        (IpAddr::V4(net_addr), IpAddr::V4(addr)) => {
+            prefix_matches(&net_addr.octets(), &addr.octets(), net.prefix_len)
+        }
+        (IpAddr::V6(net_addr), IpAddr::V6(addr)) => {
+            prefix_matches(&net_addr.octets(), &addr.octets(), net.prefix_len)
+        }
+        _ => false,
+    }
+}
+
+fn prefix_matches(net_bytes: &[u8], addr_bytes: &[u8], prefix_len: u8) -> bool {
+    (0..prefix_len as usize).all(|bit| bit_at(net_bytes, bit) == bit_at(addr_bytes, bit))
 }
 
 /// Minimal IPv4/UDP packet builder (valid header & checksum, UDP checksum=0).
@@ -143,7 +838,7 @@ fn build_ipv4_udp(
     ip[10..12].copy_from_slice(&[0, 0]); // checksum zeroed for calc
     ip[12..16].copy_from_slice(&src.octets());
     ip[16..20].copy_from_slice(&dst.octets());
-    let cksum = ipv4_checksum(&ip);
+    let cksum = ones_complement_checksum(&ip);
     ip[10..12].copy_from_slice(&cksum.to_be_bytes());
 
     let udp_len = 8 + payload.len();
@@ -160,17 +855,659 @@ fn build_ipv4_udp(
     pkt
 }
 
-fn ipv4_checksum(hdr: &[u8; 20]) -> u16 {
+/// Minimal IPv4/TCP packet builder (valid header & checksums, no options),
+/// used to exercise `nat::rewrite`'s TCP checksum path.
+fn build_ipv4_tcp(
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    payload: &[u8],
+) -> Vec<u8> {
+    let tcp_len = 20 + payload.len();
+    let total_len = 20 + tcp_len;
+    let mut ip = [0u8; 20];
+    ip[0] = 0x45;
+    ip[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+    ip[6..8].copy_from_slice(&0x4000u16.to_be_bytes());
+    ip[8] = 64;
+    ip[9] = 6; // proto = TCP
+    ip[12..16].copy_from_slice(&src.octets());
+    ip[16..20].copy_from_slice(&dst.octets());
+    let ip_cksum = ones_complement_checksum(&ip);
+    ip[10..12].copy_from_slice(&ip_cksum.to_be_bytes());
+
+    let mut tcp = vec![0u8; tcp_len];
+    tcp[0..2].copy_from_slice(&src_port.to_be_bytes());
+    tcp[2..4].copy_from_slice(&dst_port.to_be_bytes());
+    tcp[4..8].copy_from_slice(&seq.to_be_bytes());
+    tcp[12] = 5 << 4; // data offset = 5 words, no options
+    tcp[13] = 0x18; // flags = PSH|ACK
+    tcp[14..16].copy_from_slice(&64240u16.to_be_bytes()); // window
+    tcp[20..].copy_from_slice(payload);
+
+    let mut pseudo = Vec::with_capacity(12 + tcp_len);
+    pseudo.extend_from_slice(&src.octets());
+    pseudo.extend_from_slice(&dst.octets());
+    pseudo.push(0);
+    pseudo.push(6);
+    pseudo.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+    pseudo.extend_from_slice(&tcp);
+    let tcp_cksum = ones_complement_checksum(&pseudo);
+    tcp[16..18].copy_from_slice(&tcp_cksum.to_be_bytes());
+
+    let mut pkt = Vec::with_capacity(total_len);
+    pkt.extend_from_slice(&ip);
+    pkt.extend_from_slice(&tcp);
+    pkt
+}
+
+/// Minimal IPv6/UDP packet builder: a valid 40-byte fixed header plus a UDP
+/// segment. Unlike IPv4, IPv6 forbids a zero UDP checksum, so the
+/// transport checksum over the pseudo-header (source, destination,
+/// upper-layer length, next header) is mandatory here rather than
+/// optional.
+fn build_ipv6_udp(
+    src: Ipv6Addr,
+    dst: Ipv6Addr,
+    src_port: u16,
+    dst_port: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let udp_len = 8 + payload.len();
+    let mut hdr = [0u8; 40];
+    hdr[0] = 0x60; // version=6, traffic class/flow label = 0
+    hdr[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes()); // payload length
+    hdr[6] = 17; // next header = UDP
+    hdr[7] = 64; // hop limit
+    hdr[8..24].copy_from_slice(&src.octets());
+    hdr[24..40].copy_from_slice(&dst.octets());
+
+    let mut udp = [0u8; 8];
+    udp[0..2].copy_from_slice(&src_port.to_be_bytes());
+    udp[2..4].copy_from_slice(&dst_port.to_be_bytes());
+    udp[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+    udp[6..8].copy_from_slice(&[0, 0]); // filled in below once the segment is assembled
+
+    let mut pkt = Vec::with_capacity(40 + udp_len);
+    pkt.extend_from_slice(&hdr);
+    pkt.extend_from_slice(&udp);
+    pkt.extend_from_slice(payload);
+
+    let cksum = ipv6_udp_checksum(src, dst, &pkt[40..]);
+    pkt[46..48].copy_from_slice(&cksum.to_be_bytes());
+    pkt
+}
+
+/// Compute the mandatory IPv6 UDP checksum over the pseudo-header (source,
+/// destination, 32-bit upper-layer length, 3 zero bytes, next header) plus
+/// the UDP segment, with the segment's checksum field already zeroed. Per
+/// RFC 768/1122, a computed checksum of exactly 0 is transmitted as
+/// 0xFFFF instead, since 0 means "no checksum" — which IPv6 forbids.
+fn ipv6_udp_checksum(src: Ipv6Addr, dst: Ipv6Addr, transport: &[u8]) -> u16 {
+    let mut pseudo = Vec::with_capacity(40 + transport.len());
+    pseudo.extend_from_slice(&src.octets());
+    pseudo.extend_from_slice(&dst.octets());
+    pseudo.extend_from_slice(&(transport.len() as u32).to_be_bytes());
+    pseudo.extend_from_slice(&[0, 0, 0, 17]); // 3 reserved bytes + next header (UDP)
+    pseudo.extend_from_slice(transport);
+    match ones_complement_checksum(&pseudo) {
+        0 => 0xFFFF,
+        cksum => cksum,
+    }
+}
+
+/// Generic one's-complement Internet checksum (RFC 1071), shared by the
+/// IPv4 header checksum and, via `nat`, the TCP/UDP checksum over a
+/// pseudo-header. The checksum field itself must already be zeroed by the
+/// caller before the header/segment is passed in. A trailing odd byte is
+/// padded with a zero low byte, per the RFC.
+fn ones_complement_checksum(data: &[u8]) -> u16 {
     let mut sum: u32 = 0;
-    for i in (0..20).step_by(2) {
-        if i == 10 {
-            continue; // checksum field itself
-        }
-        let word = u16::from_be_bytes([hdr[i], hdr[i + 1]]) as u32;
-        sum = sum.wrapping_add(word);
+    let mut chunks = data.chunks_exact(2);
+    for word in &mut chunks {
+        sum = sum.wrapping_add(u16::from_be_bytes([word[0], word[1]]) as u32);
+    }
+    if let [last] = *chunks.remainder() {
+        sum = sum.wrapping_add((last as u32) << 8);
     }
     while (sum >> 16) != 0 {
         sum = (sum & 0xFFFF) + (sum >> 16);
     }
     !(sum as u16)
 }
+
+/// 1:1 NAT: rewrite an inner IPv4 packet's source and/or destination
+/// address (e.g. between a peer's tunnel IP and an assigned internal IP)
+/// and recompute every checksum the rewrite invalidates, the way zika
+/// does: the IPv4 header checksum always, and for TCP/UDP (the address
+/// fields feed their checksum via the pseudo-header) the transport
+/// checksum too.
+mod nat {
+    use super::ones_complement_checksum;
+    use std::net::Ipv4Addr;
+
+    /// Rewrite `packet`'s IPv4 source address in place. Returns `false`
+    /// (leaving `packet` untouched) if it isn't a well-formed IPv4 packet.
+    pub fn rewrite_source(packet: &mut [u8], new_src: Ipv4Addr) -> bool {
+        rewrite(packet, Some(new_src), None)
+    }
+
+    /// Rewrite `packet`'s IPv4 destination address in place. Returns
+    /// `false` (leaving `packet` untouched) if it isn't a well-formed IPv4
+    /// packet.
+    pub fn rewrite_destination(packet: &mut [u8], new_dst: Ipv4Addr) -> bool {
+        rewrite(packet, None, Some(new_dst))
+    }
+
+    fn rewrite(packet: &mut [u8], new_src: Option<Ipv4Addr>, new_dst: Option<Ipv4Addr>) -> bool {
+        if packet.len() < 20 || packet[0] >> 4 != 4 {
+            return false;
+        }
+        let ihl = (packet[0] & 0x0F) as usize * 4;
+        if ihl < 20 || packet.len() < ihl {
+            return false;
+        }
+        let proto = packet[9];
+
+        if let Some(src) = new_src {
+            packet[12..16].copy_from_slice(&src.octets());
+        }
+        if let Some(dst) = new_dst {
+            packet[16..20].copy_from_slice(&dst.octets());
+        }
+        packet[10..12].copy_from_slice(&[0, 0]);
+        let ip_cksum = ones_complement_checksum(&packet[..ihl]);
+        packet[10..12].copy_from_slice(&ip_cksum.to_be_bytes());
+
+        let src_addr = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+        let dst_addr = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+        match proto {
+            6 if packet.len() - ihl >= 20 => {
+                rewrite_transport_checksum(&mut packet[ihl..], src_addr, dst_addr, proto, 16)
+            }
+            17 if packet.len() - ihl >= 8 => {
+                rewrite_transport_checksum(&mut packet[ihl..], src_addr, dst_addr, proto, 6)
+            }
+            _ => {} // no transport checksum depends on the rewritten addresses
+        }
+        true
+    }
+
+    /// Recompute a TCP/UDP checksum over the IPv4 pseudo-header (source,
+    /// destination, zero, protocol, transport length) followed by the
+    /// transport segment itself, with its checksum field zeroed first.
+    /// `cksum_offset` is 16 for TCP, 6 for UDP. UDP additionally maps a
+    /// computed checksum of exactly 0 to 0xFFFF (RFC 768), since 0 means
+    /// "no checksum" there; TCP has no such reserved value.
+    fn rewrite_transport_checksum(
+        transport: &mut [u8],
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        proto: u8,
+        cksum_offset: usize,
+    ) {
+        transport[cksum_offset..cksum_offset + 2].copy_from_slice(&[0, 0]);
+
+        let mut pseudo = Vec::with_capacity(12 + transport.len());
+        pseudo.extend_from_slice(&src.octets());
+        pseudo.extend_from_slice(&dst.octets());
+        pseudo.push(0);
+        pseudo.push(proto);
+        pseudo.extend_from_slice(&(transport.len() as u16).to_be_bytes());
+        pseudo.extend_from_slice(transport);
+
+        let cksum = match ones_complement_checksum(&pseudo) {
+            0 if proto == 17 => 0xFFFF,
+            cksum => cksum,
+        };
+        transport[cksum_offset..cksum_offset + 2].copy_from_slice(&cksum.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A from-scratch reimplementation of the one's-complement Internet
+    /// checksum, deliberately not sharing code with `ones_complement_checksum`
+    /// so these tests catch a bug in that shared helper rather than just
+    /// agreeing with it. Valid iff summing the data with the checksum field
+    /// left in place comes out all-ones before the final complement.
+    fn independent_checksum_is_valid(data: &[u8]) -> bool {
+        let mut sum: u32 = 0;
+        let mut i = 0;
+        while i < data.len() {
+            let word = if i + 1 < data.len() {
+                (u32::from(data[i]) << 8) | u32::from(data[i + 1])
+            } else {
+                u32::from(data[i]) << 8
+            };
+            sum += word;
+            i += 2;
+        }
+        while sum > 0xFFFF {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+        sum == 0xFFFF
+    }
+
+    fn ipv4_pseudo_header(proto: u8, src: Ipv4Addr, dst: Ipv4Addr, transport: &[u8]) -> Vec<u8> {
+        let mut pseudo = Vec::with_capacity(12 + transport.len());
+        pseudo.extend_from_slice(&src.octets());
+        pseudo.extend_from_slice(&dst.octets());
+        pseudo.push(0);
+        pseudo.push(proto);
+        pseudo.extend_from_slice(&(transport.len() as u16).to_be_bytes());
+        pseudo.extend_from_slice(transport);
+        pseudo
+    }
+
+    #[test]
+    fn nat_rewrite_source_keeps_ipv4_checksum_valid() {
+        let mut pkt = build_ipv4_udp(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2),
+            12345,
+            54321,
+            b"payload",
+        );
+        assert!(nat::rewrite_source(&mut pkt, Ipv4Addr::new(192, 168, 1, 1)));
+        assert_eq!(&pkt[12..16], &[192, 168, 1, 1]);
+        assert!(independent_checksum_is_valid(&pkt[..20]));
+    }
+
+    #[test]
+    fn nat_rewrite_destination_recomputes_udp_checksum() {
+        let mut pkt = build_ipv4_udp(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2),
+            12345,
+            54321,
+            b"payload",
+        );
+        assert!(nat::rewrite_destination(&mut pkt, Ipv4Addr::new(192, 168, 1, 2)));
+        assert_eq!(&pkt[16..20], &[192, 168, 1, 2]);
+        assert!(independent_checksum_is_valid(&pkt[..20]));
+
+        let new_src = Ipv4Addr::new(pkt[12], pkt[13], pkt[14], pkt[15]);
+        let new_dst = Ipv4Addr::new(pkt[16], pkt[17], pkt[18], pkt[19]);
+        let pseudo = ipv4_pseudo_header(17, new_src, new_dst, &pkt[20..]);
+        assert!(independent_checksum_is_valid(&pseudo));
+        // The checksum is no longer the placeholder zero `build_ipv4_udp` left it at.
+        assert_ne!(&pkt[26..28], &[0, 0]);
+    }
+
+    #[test]
+    fn nat_rewrite_destination_recomputes_tcp_checksum() {
+        let mut pkt = build_ipv4_tcp(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2),
+            12345,
+            443,
+            1,
+            b"payload",
+        );
+        assert!(nat::rewrite_destination(&mut pkt, Ipv4Addr::new(192, 168, 1, 2)));
+        assert_eq!(&pkt[16..20], &[192, 168, 1, 2]);
+        assert!(independent_checksum_is_valid(&pkt[..20]));
+
+        let new_src = Ipv4Addr::new(pkt[12], pkt[13], pkt[14], pkt[15]);
+        let new_dst = Ipv4Addr::new(pkt[16], pkt[17], pkt[18], pkt[19]);
+        let pseudo = ipv4_pseudo_header(6, new_src, new_dst, &pkt[20..]);
+        assert!(independent_checksum_is_valid(&pseudo));
+    }
+
+    #[test]
+    fn nat_rejects_non_ipv4_packet() {
+        let mut not_ipv4 = vec![0x60, 0, 0, 0, 0, 0, 0, 0];
+        assert!(!nat::rewrite_source(&mut not_ipv4, Ipv4Addr::new(1, 2, 3, 4)));
+    }
+
+    #[test]
+    fn udp_transport_round_trips_a_datagram_over_loopback() {
+        let a = UdpTransport::bind(0).expect("bind A");
+        let b = UdpTransport::bind(0).expect("bind B");
+        // `local_addr()` reports the unspecified bind address; route to
+        // each other over loopback explicitly instead.
+        let addr_a: SocketAddr = (Ipv6Addr::LOCALHOST, a.local_addr().unwrap().port()).into();
+        let addr_b: SocketAddr = (Ipv6Addr::LOCALHOST, b.local_addr().unwrap().port()).into();
+
+        a.send_to(b"hello over a real socket", addr_b).unwrap();
+
+        let mut buf = vec![0u8; 256];
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+        let (n, from) = loop {
+            match b.recv_from(&mut buf) {
+                Ok(received) => break received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(std::time::Instant::now() < deadline, "timed out waiting for datagram");
+                    std::thread::sleep(std::time::Duration::from_millis(5));
+                }
+                Err(e) => panic!("recv error: {e}"),
+            }
+        };
+
+        assert_eq!(&buf[..n], b"hello over a real socket");
+        // Only the port is compared: A's socket is bound to the unspecified
+        // address, so the source IP is picked by the kernel at send time
+        // rather than fixed by the bind.
+        assert_eq!(from.port(), addr_a.port());
+
+        b.send_to(b"and back", from).unwrap();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+        let (n, _) = loop {
+            match a.recv_from(&mut buf) {
+                Ok(received) => break received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(std::time::Instant::now() < deadline, "timed out waiting for reply");
+                    std::thread::sleep(std::time::Duration::from_millis(5));
+                }
+                Err(e) => panic!("recv error: {e}"),
+            }
+        };
+        assert_eq!(&buf[..n], b"and back");
+    }
+
+    #[test]
+    fn build_ipv6_udp_never_emits_zero_checksum() {
+        // This payload makes the raw one's-complement sum come out to
+        // exactly 0, which must be transmitted as 0xFFFF instead.
+        let pkt = build_ipv6_udp(
+            Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1),
+            Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2),
+            12345,
+            54321,
+            &[0x01, 0x6b],
+        );
+        assert_ne!(&pkt[46..48], &[0, 0]);
+        assert_eq!(&pkt[46..48], &[0xFF, 0xFF]);
+    }
+
+    /// End-to-end: an IPv6/UDP inner packet encapsulated on one `Device`
+    /// routes through the other's AllowedIPs (now covering a v6 net too)
+    /// and arrives at the tunnel byte-identical, exercising the same
+    /// `WriteToTunnelV6` path that `WriteToTunnelV4` already covers above.
+    #[test]
+    fn ipv6_round_trip_delivers_byte_identical_packet() {
+        let a_sk = StaticSecret::random_from_rng(OsRng);
+        let b_sk = StaticSecret::random_from_rng(OsRng);
+        let a_pk = PublicKey::from(&a_sk);
+        let b_pk = PublicKey::from(&b_sk);
+        let a_tunn = Tunn::new(a_sk, b_pk, None, Some(25), 0, None).unwrap();
+        let b_tunn = Tunn::new(b_sk, a_pk, None, Some(25), 1, None).unwrap();
+
+        let addr_a: SocketAddr = ([127, 0, 0, 1], 61820).into();
+        let addr_b: SocketAddr = ([127, 0, 0, 1], 61821).into();
+        let (transport_a, transport_b) = InMemoryTransport::pair(addr_a, addr_b);
+
+        let a_net = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+        let b_net = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+
+        let dev_a = Device::new();
+        dev_a.add_peer(
+            b_pk,
+            a_tunn,
+            Some(addr_b),
+            vec![IpNet::new(IpAddr::V6(b_net), 128).unwrap()],
+        );
+        let dev_b = Device::new();
+        dev_b.add_peer(
+            a_pk,
+            b_tunn,
+            None,
+            vec![IpNet::new(IpAddr::V6(a_net), 128).unwrap()],
+        );
+
+        let mut a_tun: VecDeque<Vec<u8>> = VecDeque::new();
+        let mut b_tun: VecDeque<Vec<u8>> = VecDeque::new();
+
+        let mut out = vec![0u8; 2048];
+        if let Some((pkt, dest)) = dev_a.initiate_handshake(&b_pk, &mut out) {
+            transport_a.send_to(pkt, dest).unwrap();
+        }
+        pump(&dev_a, &transport_a, &dev_b, &transport_b, &mut a_tun, &mut b_tun);
+
+        let inner = build_ipv6_udp(a_net, b_net, 12345, 54321, b"hello over ipv6".as_ref());
+
+        let mut enc_buf = vec![0u8; inner.len() + 256];
+        let (wg, dest) = dev_a
+            .encapsulate(&inner, &mut enc_buf)
+            .expect("route and handshake should be ready");
+        transport_a.send_to(wg, dest).unwrap();
+
+        pump(&dev_a, &transport_a, &dev_b, &transport_b, &mut a_tun, &mut b_tun);
+
+        let received = b_tun.pop_front().expect("B should receive inner packet");
+        assert_eq!(inner, received);
+    }
+
+    #[test]
+    fn ip_net_rejects_prefix_len_past_address_width() {
+        assert!(IpNet::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 33).is_none());
+        assert!(IpNet::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 32).is_some());
+        assert!(IpNet::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 129).is_none());
+        assert!(IpNet::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 128).is_some());
+    }
+
+    #[test]
+    fn allowed_ips_remove_peer_clears_its_routes() {
+        let pubkey = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let net = IpNet::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 24).unwrap();
+
+        let mut table = AllowedIps::new();
+        table.insert(net, pubkey);
+        assert_eq!(table.find(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5))), Some(pubkey));
+
+        table.remove_peer(&pubkey);
+        assert_eq!(table.find(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5))), None);
+    }
+
+    #[test]
+    fn device_remove_peer_drops_its_route() {
+        let a_sk = StaticSecret::random_from_rng(OsRng);
+        let b_sk = StaticSecret::random_from_rng(OsRng);
+        let b_pk = PublicKey::from(&b_sk);
+        let b_tunn = Tunn::new(b_sk, PublicKey::from(&a_sk), None, Some(25), 0, None).unwrap();
+
+        let b_net = IpNet::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 32).unwrap();
+        let dev = Device::new();
+        dev.add_peer(b_pk, b_tunn, Some(([127, 0, 0, 1], 61824).into()), vec![b_net]);
+
+        let inner = build_ipv4_udp(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2),
+            12345,
+            54321,
+            b"hi",
+        );
+        let mut buf = vec![0u8; inner.len() + 256];
+        assert!(
+            dev.encapsulate(&inner, &mut buf).is_some(),
+            "route to B should exist before remove_peer"
+        );
+
+        dev.remove_peer(&b_pk);
+
+        let mut buf = vec![0u8; inner.len() + 256];
+        assert!(
+            dev.encapsulate(&inner, &mut buf).is_none(),
+            "route to B should be gone after remove_peer"
+        );
+    }
+
+    #[test]
+    fn rejects_exact_duplicate() {
+        let mut filter = ReplayFilter::new();
+        assert!(filter.validate_and_update(5));
+        assert!(!filter.validate_and_update(5));
+    }
+
+    #[test]
+    fn accepts_in_order_and_reordered_within_window() {
+        let mut filter = ReplayFilter::new();
+        assert!(filter.validate_and_update(1));
+        assert!(filter.validate_and_update(2));
+        assert!(filter.validate_and_update(10));
+        // 3 is behind the current high-water mark (10) but still inside the
+        // sliding window, and hasn't been seen yet.
+        assert!(filter.validate_and_update(3));
+        // Replaying that same reordered counter must now be rejected.
+        assert!(!filter.validate_and_update(3));
+    }
+
+    #[test]
+    fn rejects_counter_too_far_behind_window() {
+        let mut filter = ReplayFilter::new();
+        assert!(filter.validate_and_update(WINDOW_SIZE + 1000));
+        assert!(!filter.validate_and_update(0));
+    }
+
+    #[test]
+    fn validate_and_update_does_not_overflow_near_u64_max() {
+        let mut filter = ReplayFilter::new();
+        assert!(filter.validate_and_update(u64::MAX));
+        // Must not panic computing `seq + WINDOW_SIZE` here.
+        assert!(!filter.validate_and_update(u64::MAX - 1));
+    }
+
+    #[test]
+    fn drain_ready_reassembles_out_of_order_completions() {
+        let mut pending = BTreeMap::new();
+        let mut expected = 0u64;
+
+        // Job 1 finishes before job 0: nothing is ready yet.
+        pending.insert(1, vec![DeviceEvent::ToTunnelV4(vec![1], Ipv4Addr::new(10, 0, 0, 1))]);
+        assert!(drain_ready(&mut pending, &mut expected).is_empty());
+        assert_eq!(expected, 0);
+
+        // Job 0 arrives, unblocking both 0 and the already-buffered 1, in order.
+        pending.insert(0, vec![DeviceEvent::ToTunnelV4(vec![0], Ipv4Addr::new(10, 0, 0, 1))]);
+        let ready = drain_ready(&mut pending, &mut expected);
+        assert_eq!(ready.len(), 2);
+        let payload = |events: &[DeviceEvent]| match &events[0] {
+            DeviceEvent::ToTunnelV4(p, _) => p.clone(),
+            _ => panic!("expected ToTunnelV4"),
+        };
+        assert_eq!(payload(&ready[0]), vec![0]);
+        assert_eq!(payload(&ready[1]), vec![1]);
+        assert_eq!(expected, 2);
+    }
+
+    /// End-to-end: two data packets encapsulated on A are decapsulated on B
+    /// through a real `ParallelQueue` (not the serial `process_incoming`
+    /// path), confirming both that `Device::spawn`/`submit`/`poll_ordered`
+    /// actually work and that `ToNetwork`-free results still come back in
+    /// submission order.
+    #[test]
+    fn parallel_queue_delivers_decapsulated_events_in_order() {
+        let a_sk = StaticSecret::random_from_rng(OsRng);
+        let b_sk = StaticSecret::random_from_rng(OsRng);
+        let a_pk = PublicKey::from(&a_sk);
+        let b_pk = PublicKey::from(&b_sk);
+        let a_tunn = Tunn::new(a_sk, b_pk, None, Some(25), 0, None).unwrap();
+        let b_tunn = Tunn::new(b_sk, a_pk, None, Some(25), 1, None).unwrap();
+
+        let addr_a: SocketAddr = ([127, 0, 0, 1], 61822).into();
+        let addr_b: SocketAddr = ([127, 0, 0, 1], 61823).into();
+        let (transport_a, transport_b) = InMemoryTransport::pair(addr_a, addr_b);
+
+        let a_net = Ipv4Addr::new(10, 10, 0, 1);
+        let b_net = Ipv4Addr::new(10, 10, 0, 2);
+
+        let dev_a = Device::new();
+        dev_a.add_peer(
+            b_pk,
+            a_tunn,
+            Some(addr_b),
+            vec![IpNet::new(IpAddr::V4(b_net), 32).unwrap()],
+        );
+        let dev_b = Arc::new(Device::new());
+        dev_b.add_peer(
+            a_pk,
+            b_tunn,
+            None,
+            vec![IpNet::new(IpAddr::V4(a_net), 32).unwrap()],
+        );
+
+        let mut a_tun: VecDeque<Vec<u8>> = VecDeque::new();
+        let mut b_tun: VecDeque<Vec<u8>> = VecDeque::new();
+
+        let mut out = vec![0u8; 2048];
+        if let Some((pkt, dest)) = dev_a.initiate_handshake(&b_pk, &mut out) {
+            transport_a.send_to(pkt, dest).unwrap();
+        }
+        pump(&dev_a, &transport_a, &dev_b, &transport_b, &mut a_tun, &mut b_tun);
+
+        let inner1 = build_ipv4_udp(a_net, b_net, 12345, 54321, b"first");
+        let inner2 = build_ipv4_udp(a_net, b_net, 12345, 54321, b"second");
+        let mut enc_buf1 = vec![0u8; inner1.len() + 256];
+        let mut enc_buf2 = vec![0u8; inner2.len() + 256];
+        let (wg1, dest1) = dev_a.encapsulate(&inner1, &mut enc_buf1).unwrap();
+        let wg1 = wg1.to_vec();
+        transport_a.send_to(&wg1, dest1).unwrap();
+        let (wg2, dest2) = dev_a.encapsulate(&inner2, &mut enc_buf2).unwrap();
+        transport_a.send_to(wg2, dest2).unwrap();
+
+        // Feed both datagrams through a real worker pool instead of the
+        // serial `process_incoming` path.
+        let mut queue = dev_b.spawn(2);
+        let mut buf = vec![0u8; 65536];
+        let mut submitted = 0usize;
+        loop {
+            match transport_b.recv_from(&mut buf) {
+                Ok((n, from)) => {
+                    queue.submit(buf[..n].to_vec(), from);
+                    submitted += 1;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => panic!("recv error: {e}"),
+            }
+        }
+
+        let mut delivered = Vec::new();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+        while delivered.len() < submitted {
+            for events in queue.poll_ordered() {
+                for event in events {
+                    if let DeviceEvent::ToTunnelV4(inner, _) = event {
+                        delivered.push(inner);
+                    }
+                }
+            }
+            assert!(
+                std::time::Instant::now() < deadline,
+                "timed out waiting for parallel queue delivery"
+            );
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+
+        assert_eq!(delivered, vec![inner1, inner2]);
+    }
+
+    #[test]
+    fn worker_index_for_is_stable_per_session_and_bounded() {
+        let workers = 4;
+        let mut datagram = vec![0u8; 32];
+        datagram[4..8].copy_from_slice(&42u32.to_le_bytes());
+        let first = worker_index_for(&datagram, workers);
+        assert!(first < workers);
+
+        // Only the counter (bytes 8..16) changes between data packets of the
+        // same session; the worker selection must not move.
+        datagram[8..16].copy_from_slice(&999u64.to_le_bytes());
+        assert_eq!(worker_index_for(&datagram, workers), first);
+
+        // Too short to carry a session index: falls back to worker 0 rather
+        // than panicking on an out-of-bounds slice.
+        assert_eq!(worker_index_for(&[0u8; 3], workers), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one worker")]
+    fn spawn_with_zero_workers_panics_immediately_instead_of_in_submit() {
+        let dev = Arc::new(Device::new());
+        let _ = dev.spawn(0);
+    }
+}